@@ -3,54 +3,222 @@
     SPDX-License-Identifier: Apache-2.0
 */
 use log::{info, warn};
+use std::collections::hash_map::Entry as MapEntry;
 use std::collections::HashMap;
-use std::collections::VecDeque;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex as SyncMutex;
+use std::sync::{Arc, OnceLock, Weak};
+use tokio::sync::{Mutex, RwLock};
 use tokio::time::interval;
 use tokio::time::Duration;
 use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
 
 use pnet::packet::ip::IpNextHeaderProtocol;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv6Addr};
+
+/// Key identifying a rate-limited flow: (src_ip, protocol, dest_port).
+///
+/// IPv4 addresses are keyed per-address. IPv6 addresses are keyed by their
+/// /64 prefix (see [`bucket_addr`]), since a single client can otherwise
+/// source traffic from any of 2^64 addresses in its allocation and exhaust
+/// `max_routes` by rotating through them.
+type RouteKey = (IpAddr, IpNextHeaderProtocol, u16);
+
+/// Normalizes `addr` to its rate-limiting bucket: unchanged for IPv4,
+/// masked to the /64 prefix for IPv6.
+fn bucket_addr(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(_) => addr,
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Security {
     background_task_period: Duration,
-    cancel_token: Mutex<CancellationToken>,
-    rate_limiter: Mutex<RateLimiter>,
+    cancel_token: SyncMutex<CancellationToken>,
+    rate_limiter: RateLimiter,
+}
+
+/// Monotonic reference point the whole table's timestamps are relative to,
+/// captured once on first use.
+static PROCESS_START: OnceLock<Instant> = OnceLock::new();
+
+/// Compact timestamp: seconds elapsed since process start, packed into a
+/// `u32` instead of a 16-byte `Instant`. A `u32` of seconds rolls over after
+/// ~136 years, which is a non-issue for a process-lifetime counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct InstantSecs(u32);
+
+impl InstantSecs {
+    fn now() -> Self {
+        let start = *PROCESS_START.get_or_init(Instant::now);
+        Self(start.elapsed().as_secs() as u32)
+    }
+
+    /// Nanoseconds elapsed between `earlier` and `self`, at one-second
+    /// resolution. Because both endpoints are truncated to whole seconds, a
+    /// window configured below ~1s is effectively rounded up to 1s, and a
+    /// flow can refill a full window's worth of tokens by sitting on either
+    /// side of a second boundary (up to ~2x the intended allowance).
+    fn elapsed_ns_since(self, earlier: InstantSecs) -> u64 {
+        u64::from(self.0.saturating_sub(earlier.0)) * 1_000_000_000
+    }
+}
+
+/// The two independent dimensions a flow is rate-limited on, as in
+/// cloud-hypervisor's rate limiter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenType {
+    Ops,
+    Bytes,
+}
+
+const TOKEN_TYPES: [TokenType; 2] = [TokenType::Ops, TokenType::Bytes];
+
+/// Token-bucket state for one dimension ([`TokenType`]) of a flow.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    last_time: InstantSecs,
+    tokens: u64,
+}
+
+/// Per-flow state: one [`Bucket`] per [`TokenType`].
+#[derive(Debug)]
+struct Entry {
+    buckets: [Bucket; TOKEN_TYPES.len()],
+}
+
+/// Named rate-limit classes, so e.g. TCP:22 can be capped more strictly than
+/// UDP:53 instead of every flow sharing one global budget. Traffic that
+/// doesn't match a named class falls back to [`LimitClass::Default`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LimitClass {
+    Ssh,
+    Dns,
+    Default,
+}
+
+const LIMIT_CLASSES: [LimitClass; 3] = [LimitClass::Ssh, LimitClass::Dns, LimitClass::Default];
+
+impl LimitClass {
+    const TCP: u8 = 6;
+    const UDP: u8 = 17;
+
+    /// Classifies an incoming (protocol, dest_port) tuple into its limit class.
+    fn classify(protocol: IpNextHeaderProtocol, dest_port: u16) -> Self {
+        match (protocol.0, dest_port) {
+            (Self::TCP, 22) => LimitClass::Ssh,
+            (Self::UDP, 53) => LimitClass::Dns,
+            _ => LimitClass::Default,
+        }
+    }
+}
+
+/// Configuration for one [`LimitClass`]: `max_requests` packets and
+/// `max_bytes` bytes are each allowed per their own window, and at most
+/// `max_routes` distinct flows are tracked for this class at once --
+/// independently of how many other classes' flows are in the shared route
+/// table, so one class flooding in can't deny brand-new flows of another.
+///
+/// `requests_window` and `bytes_window` are tracked at one-second resolution
+/// (see [`InstantSecs`]), so they should be configured no shorter than ~1s;
+/// a shorter window is effectively rounded up to 1s and can let a flow
+/// refill its full budget on a sub-second second-boundary crossing.
+#[derive(Debug, Clone, Copy)]
+pub struct ClassLimits {
+    pub max_requests: u64,
+    pub requests_window: Duration,
+    pub max_bytes: u64,
+    pub bytes_window: Duration,
+    pub max_routes: usize,
+}
+
+/// [`ClassLimits`] resolved into the token-bucket constants `is_allowed` uses
+/// on its hot path.
+#[derive(Debug, Clone, Copy)]
+struct ResolvedClass {
+    max_routes: usize,
+    packet_cost: [u64; TOKEN_TYPES.len()], // Nanoseconds of token budget consumed per unit, by TokenType
+    max_tokens: [u64; TOKEN_TYPES.len()],  // Burst ceiling, in nanoseconds of token budget, by TokenType
+}
+
+impl From<ClassLimits> for ResolvedClass {
+    fn from(limits: ClassLimits) -> Self {
+        let ops_max_tokens = limits.requests_window.as_nanos() as u64;
+        let bytes_max_tokens = limits.bytes_window.as_nanos() as u64;
+        Self {
+            max_routes: limits.max_routes,
+            packet_cost: [
+                ops_max_tokens / limits.max_requests.max(1),
+                bytes_max_tokens / limits.max_bytes.max(1),
+            ],
+            max_tokens: [ops_max_tokens, bytes_max_tokens],
+        }
+    }
 }
 
 /// Represents a rate limiter for (src_ip, protocol, dest_port) tuples.
-#[derive(Debug, Clone)]
+///
+/// Uses a token-bucket model (as in WireGuard's rate limiter): each flow
+/// accrues tokens over time at a fixed rate and spends them per accepted
+/// packet, up to a configurable burst allowance. Each flow is limited on two
+/// independent dimensions (operations and bytes, see [`TokenType`]), as in
+/// cloud-hypervisor's rate limiter, so a flow within its packet budget can't
+/// still flood bytes. Limits are further resolved per [`LimitClass`], via a
+/// fixed array indexed by `class as usize` (an enum_map-style table), so
+/// classifying and looking up a tuple's limits is O(1) and allocation-free.
+///
+/// The route table is an `RwLock<HashMap<_, Mutex<Entry>>>` so that checks
+/// against different flows only contend on the read lock; only inserting a
+/// brand-new flow needs the write lock. `route_counts` tracks how many of
+/// those flows belong to each [`LimitClass`], so `max_routes` is enforced
+/// per class rather than against the shared table's total size -- otherwise
+/// a flood of one class's flows could fill the table and deny brand-new
+/// flows of an unrelated, otherwise-under-budget class.
+#[derive(Debug)]
 pub struct RateLimiter {
-    enabled: bool,
-    routes: HashMap<(Ipv4Addr, IpNextHeaderProtocol, u16), VecDeque<Instant>>, // Key: (src_ip, protocol, dest_port)
-    max_routes: usize,
-    max_requests: usize,         // Max requests per time window
-    window: Duration,            // Sliding time window
+    enabled: AtomicBool,
+    routes: RwLock<HashMap<RouteKey, Mutex<Entry>>>,
+    classes: [ResolvedClass; LIMIT_CLASSES.len()],
+    route_counts: [AtomicUsize; LIMIT_CLASSES.len()],
+    cleaning: AtomicBool,        // Guards against overlapping GC passes
     _cleanup_interval: Duration, // How often to remove stale IP
 }
 
 impl Security {
-    pub fn new(rate_limiter: &RateLimiter) -> Arc<Self> {
+    pub fn new(rate_limiter: RateLimiter) -> Arc<Self> {
         const BACKGROUND_TASK_PERIOD: Duration = Duration::from_millis(1000);
         let security = Arc::new(Self {
             background_task_period: BACKGROUND_TASK_PERIOD,
-            cancel_token: Mutex::new(CancellationToken::default()),
-            rate_limiter: Mutex::new(rate_limiter.clone()),
+            cancel_token: SyncMutex::new(CancellationToken::default()),
+            rate_limiter,
         });
 
-        // Spawn the background cleanup task without moving `security`
-        let security_clone = Arc::clone(&security);
-        tokio::spawn(async move { security_clone.background_task().await });
+        // Hold only a Weak reference so the task doesn't keep `security` alive;
+        // otherwise it would never observe the last strong `Arc` being dropped.
+        let weak_security = Arc::downgrade(&security);
+        tokio::spawn(Self::background_task(weak_security));
         security
     }
-    /// Background task to clean up old keys (inactive IPs)
-    async fn background_task(self: Arc<Self>) {
-        let mut interval = interval(self.background_task_period);
-        let cancel_token = &self.cancel_token.lock().await;
+
+    /// Background task to clean up old keys (inactive IPs).
+    ///
+    /// Clones the `CancellationToken` out of its mutex once up front instead
+    /// of holding the lock for the task's entire lifetime, so
+    /// `set_cancel_token` doesn't deadlock against it.
+    async fn background_task(weak_security: Weak<Self>) {
+        let Some(security) = weak_security.upgrade() else {
+            return;
+        };
+        let mut interval = interval(security.background_task_period);
+        let cancel_token = security.cancel_token.lock().unwrap().clone();
+        drop(security);
+
         let mut rate_limiter_cnt = 0;
         loop {
             tokio::select! {
@@ -62,12 +230,16 @@ impl Security {
                     }
                 _ = async {
                     interval.tick().await;
-                    let mut rate_limiter_lock = self.rate_limiter.lock().await;
                     rate_limiter_cnt = (rate_limiter_cnt + 1) % 10;
 
                     if rate_limiter_cnt ==0{
-                        rate_limiter_lock.cleanup_old_requests();
-
+                        if let Some(security) = weak_security.upgrade() {
+                            security.rate_limiter.cleanup_old_requests().await;
+                        } else {
+                            // `Security` is gone but our token was never cancelled
+                            // (it was replaced via `set_cancel_token`): stop anyway.
+                            cancel_token.cancel();
+                        }
                     }
                 }=> {}
             }
@@ -76,137 +248,382 @@ impl Security {
 
     pub async fn is_packet_secure(
         self: Arc<Self>,
-        src_ip: Ipv4Addr,
+        src_ip: IpAddr,
         protocol: IpNextHeaderProtocol,
         src_port: u16,
         dest_port: u16,
+        payload_len: u64,
     ) -> bool {
         if dest_port == 0 || src_port == 0 {
             return false;
         }
 
-        let mut rate_limiter_lock = self.rate_limiter.lock().await;
-
-        if !rate_limiter_lock.enabled {
+        if !self.rate_limiter.enabled.load(Ordering::Relaxed) {
             return true;
         }
 
-        rate_limiter_lock.is_allowed(src_ip, protocol, dest_port)
+        self.rate_limiter
+            .is_allowed(src_ip, protocol, dest_port, payload_len)
+            .await
     }
 
     pub async fn set_rate_limiter(self: Arc<Self>, enabled: bool) {
-        let mut rate_limiter_lock = self.rate_limiter.lock().await;
-
-        rate_limiter_lock.enabled = enabled;
+        self.rate_limiter.enabled.store(enabled, Ordering::Relaxed);
     }
     pub async fn set_cancel_token(self: Arc<Self>, token: CancellationToken) {
-        let mut cancel_token = self.cancel_token.lock().await;
-        *cancel_token = token;
+        *self.cancel_token.lock().unwrap() = token;
+    }
+}
+
+impl Drop for Security {
+    /// Cancels the background GC task so it exits once the last `Arc<Security>` is released.
+    fn drop(&mut self) {
+        self.cancel_token.lock().unwrap().cancel();
     }
 }
 
 impl RateLimiter {
-    /// Creates a new rate limiter with given limits.
+    /// Creates a new rate limiter, with one [`ClassLimits`] per [`LIMIT_CLASSES`] entry
+    /// (in that order) resolving the budget each incoming tuple is classified into.
     pub fn new(
         enabled: bool,
-        max_requests: usize,
-        window: Duration,
+        classes: [ClassLimits; LIMIT_CLASSES.len()],
         _cleanup_interval: Duration,
     ) -> Self {
         Self {
-            enabled,
-            routes: HashMap::new(),
-            max_routes: 50,
-            max_requests: if max_requests > 1 {
-                max_requests - 1
-            } else {
-                max_requests
-            },
-            window,
+            enabled: AtomicBool::new(enabled),
+            routes: RwLock::new(HashMap::new()),
+            classes: classes.map(ResolvedClass::from),
+            route_counts: std::array::from_fn(|_| AtomicUsize::new(0)),
+            cleaning: AtomicBool::new(false),
             _cleanup_interval,
         }
     }
 
-    /// Checks if a request from `(src_ip, protocol, dest_port)` is allowed.
-    fn is_allowed(
-        &mut self,
-        src_ip: Ipv4Addr,
+    /// Checks if a request from `(src_ip, protocol, dest_port)` is allowed,
+    /// consuming 1 `Ops` token and `payload_len` `Bytes` tokens from the
+    /// budget of the [`LimitClass`] that tuple classifies into.
+    ///
+    /// Existing flows only take the table's read lock plus their own entry
+    /// lock, so checks for different flows proceed in parallel; only
+    /// inserting a brand-new flow needs the write lock.
+    async fn is_allowed(
+        &self,
+        src_ip: IpAddr,
         protocol: IpNextHeaderProtocol,
         dest_port: u16,
+        payload_len: u64,
     ) -> bool {
-        let now = Instant::now();
-        let key = (src_ip, protocol, dest_port);
+        let now = InstantSecs::now();
+        let key: RouteKey = (bucket_addr(src_ip), protocol, dest_port);
+        let class_idx = LimitClass::classify(protocol, dest_port) as usize;
+        let class = &self.classes[class_idx];
 
-        // Prevent memory explosion
-        if self.routes.len() >= self.max_routes && !self.routes.contains_key(&key) {
-            return false;
+        {
+            let routes = self.routes.read().await;
+            if let Some(entry_lock) = routes.get(&key) {
+                let mut entry = entry_lock.lock().await;
+                return Self::spend(class, &mut entry, now, payload_len);
+            }
         }
-        // Get or insert key with an empty vector
-        let timestamps = self.routes.entry(key).or_default();
-
-        // Remove expired timestamps (only keep recent ones within the window)
-        timestamps.retain(|&t| now.duration_since(t) <= self.window);
-
-        // Check if within rate limit
-        if timestamps.len() < self.max_requests {
-            timestamps.push_back(now);
-            true
-        } else {
-            false
+
+        let mut routes = self.routes.write().await;
+        let entry_lock = match routes.entry(key) {
+            MapEntry::Occupied(occupied) => occupied.into_mut(),
+            MapEntry::Vacant(vacant) => {
+                // Prevent memory explosion, per class so one class's flood
+                // can't starve brand-new flows of another, still-under-budget class.
+                if self.route_counts[class_idx].load(Ordering::Relaxed) >= class.max_routes {
+                    return false;
+                }
+                self.route_counts[class_idx].fetch_add(1, Ordering::Relaxed);
+                vacant.insert(Mutex::new(Self::new_entry(class, now)))
+            }
+        };
+        let mut entry = entry_lock.lock().await;
+        Self::spend(class, &mut entry, now, payload_len)
+    }
+
+    /// Builds a fresh, fully-charged [`Entry`] for a new flow in `class`.
+    fn new_entry(class: &ResolvedClass, now: InstantSecs) -> Entry {
+        Entry {
+            buckets: TOKEN_TYPES.map(|t| Bucket {
+                last_time: now,
+                tokens: class.max_tokens[t as usize],
+            }),
         }
     }
 
-    /// Cleanup function to remove expired requests
-    fn cleanup_old_requests(&mut self) {
-        let now = Instant::now();
+    /// Refills `bucket` up to `class`'s ceiling for `token_type` and returns
+    /// the resulting token count, without mutating `bucket`.
+    fn refill(class: &ResolvedClass, bucket: &Bucket, now: InstantSecs, token_type: TokenType) -> u64 {
+        let elapsed_ns = now.elapsed_ns_since(bucket.last_time);
+        (bucket.tokens + elapsed_ns).min(class.max_tokens[token_type as usize])
+    }
 
-        self.routes.retain(|_, timestamps| {
-            timestamps.retain(|&t| now.duration_since(t) <= self.window);
-            !timestamps.is_empty()
-        });
+    /// Spends 1 `Ops` token and `payload_len` `Bytes` tokens from `entry`'s
+    /// buckets against `class`'s budget; denies without mutating either
+    /// bucket if either is exhausted.
+    fn spend(class: &ResolvedClass, entry: &mut Entry, now: InstantSecs, payload_len: u64) -> bool {
+        let ops_cost = class.packet_cost[TokenType::Ops as usize];
+        let bytes_cost = class.packet_cost[TokenType::Bytes as usize].saturating_mul(payload_len);
+
+        let ops_tokens = Self::refill(class, &entry.buckets[TokenType::Ops as usize], now, TokenType::Ops);
+        let bytes_tokens = Self::refill(
+            class,
+            &entry.buckets[TokenType::Bytes as usize],
+            now,
+            TokenType::Bytes,
+        );
 
-        info!("Cleanup done: Active routes: {}", self.routes.len());
+        if ops_tokens < ops_cost || bytes_tokens < bytes_cost {
+            return false;
+        }
+
+        entry.buckets[TokenType::Ops as usize] = Bucket {
+            last_time: now,
+            tokens: ops_tokens - ops_cost,
+        };
+        entry.buckets[TokenType::Bytes as usize] = Bucket {
+            last_time: now,
+            tokens: bytes_tokens - bytes_cost,
+        };
+        true
+    }
+
+    /// Cleanup function to remove idle flows (those refilled back to a full bucket on every dimension).
+    ///
+    /// Keys are snapshotted under a brief read lock rather than held for the
+    /// whole scan: under tokio's writer-preferring `RwLock`, a single queued
+    /// insert would otherwise block every subsequent reader until the scan's
+    /// sequence of per-entry `.await`s finished, stalling hot-path checks on
+    /// other flows. Each key is then re-inspected under its own short-lived
+    /// read lock, and the final write lock is only taken to perform removals.
+    async fn cleanup_old_requests(&self) {
+        // Skip this pass if a previous one is still running.
+        if self.cleaning.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let now = InstantSecs::now();
+        let keys: Vec<RouteKey> = self.routes.read().await.keys().copied().collect();
+
+        let mut idle = Vec::new();
+        for key in keys {
+            let routes = self.routes.read().await;
+            let Some(entry_lock) = routes.get(&key) else {
+                continue;
+            };
+            let entry = entry_lock.lock().await;
+            let class = &self.classes[LimitClass::classify(key.1, key.2) as usize];
+            let fully_refilled = TOKEN_TYPES.iter().all(|&t| {
+                Self::refill(class, &entry.buckets[t as usize], now, t) >= class.max_tokens[t as usize]
+            });
+            drop(entry);
+            drop(routes);
+            if fully_refilled {
+                idle.push(key);
+            }
+        }
+
+        let mut routes = self.routes.write().await;
+        for key in &idle {
+            if routes.remove(key).is_some() {
+                let class_idx = LimitClass::classify(key.1, key.2) as usize;
+                self.route_counts[class_idx].fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+
+        info!("Cleanup done: Active routes: {}", routes.len());
+        self.cleaning.store(false, Ordering::Release);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::Ipv4Addr;
     use std::thread::sleep;
     use std::time::Duration;
 
-    #[test]
-    fn test_cleanup_old_requests() {
-        let mut rate_limiter = RateLimiter::new(
+    /// Gives every [`LimitClass`] the same budget, for tests that don't care
+    /// about per-class differences.
+    fn uniform_classes(
+        max_requests: u64,
+        requests_window: Duration,
+        max_bytes: u64,
+        bytes_window: Duration,
+        max_routes: usize,
+    ) -> [ClassLimits; LIMIT_CLASSES.len()] {
+        [ClassLimits {
+            max_requests,
+            requests_window,
+            max_bytes,
+            bytes_window,
+            max_routes,
+        }; LIMIT_CLASSES.len()]
+    }
+
+    #[tokio::test]
+    async fn test_drop_cancels_background_task() {
+        let rate_limiter = RateLimiter::new(
             true,
-            5,
-            Duration::from_millis(100),
+            uniform_classes(5, Duration::from_secs(1), 1_000, Duration::from_secs(1), 50),
             Duration::from_millis(50),
         );
+        let security = Security::new(rate_limiter);
+        let cancel_token = security.cancel_token.lock().unwrap().clone();
+        assert!(!cancel_token.is_cancelled());
+
+        drop(security);
 
-        let src_ip = Ipv4Addr::new(192, 168, 1, 1);
+        assert!(cancel_token.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_old_requests() {
+        let rate_limiter = RateLimiter::new(
+            true,
+            uniform_classes(5, Duration::from_secs(1), 1_000, Duration::from_secs(1), 50),
+            Duration::from_millis(50),
+        );
+
+        let src_ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
         let protocol = IpNextHeaderProtocol::new(6); // TCP
         let dest_port = 8080;
 
         let key = (src_ip, protocol, dest_port);
+        let empty_bucket = Bucket {
+            last_time: InstantSecs::now(),
+            tokens: 0,
+        };
 
-        // Add some timestamps (some should expire)
-        let now = Instant::now();
-        rate_limiter.routes.insert(
+        rate_limiter.routes.write().await.insert(
             key,
-            VecDeque::from(vec![
-                now - Duration::from_millis(200), // Expired
-                now - Duration::from_millis(50),  // Valid
-            ]),
+            Mutex::new(Entry {
+                buckets: [empty_bucket, empty_bucket],
+            }),
         );
 
-        // Ensure the entry exists before cleanup
-        assert_eq!(rate_limiter.routes.get(&key).unwrap().len(), 2);
+        assert_eq!(rate_limiter.routes.read().await.len(), 1);
+
+        // Wait for both buckets to fully refill (1s windows).
+        sleep(Duration::from_millis(1100));
+
+        // Call cleanup function: the idle, fully-refilled flow is dropped.
+        rate_limiter.cleanup_old_requests().await;
 
-        // Call cleanup function (log_count doesn't affect functionality)
-        rate_limiter.cleanup_old_requests();
+        assert_eq!(rate_limiter.routes.read().await.len(), 0);
+    }
+
+    #[test]
+    fn test_ipv6_same_prefix_shares_bucket() {
+        let a: IpAddr = "2001:db8::1".parse().unwrap();
+        let b: IpAddr = "2001:db8::ffff:ffff:ffff:ffff".parse().unwrap();
+
+        assert_eq!(bucket_addr(a), bucket_addr(b));
+    }
+
+    #[test]
+    fn test_ipv6_different_prefix_separate_buckets() {
+        let a: IpAddr = "2001:db8:0:1::1".parse().unwrap();
+        let b: IpAddr = "2001:db8:0:2::1".parse().unwrap();
+
+        assert_ne!(bucket_addr(a), bucket_addr(b));
+    }
+
+    #[test]
+    fn test_ipv4_bucket_is_unchanged() {
+        let addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        assert_eq!(bucket_addr(addr), addr);
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_same_prefix_shares_rate_limit() {
+        let rate_limiter = RateLimiter::new(
+            true,
+            uniform_classes(1, Duration::from_secs(1), 1_000, Duration::from_secs(1), 50),
+            Duration::from_millis(50),
+        );
+        let protocol = IpNextHeaderProtocol::new(6); // TCP
+        let dest_port = 8080;
+
+        let a: IpAddr = "2001:db8::1".parse().unwrap();
+        let b: IpAddr = "2001:db8::ffff:ffff:ffff:ffff".parse().unwrap();
+
+        // `a` spends the shared /64 bucket's only token.
+        assert!(rate_limiter.is_allowed(a, protocol, dest_port, 1).await);
+        // `b` is in the same /64, so it shares `a`'s bucket and is throttled.
+        assert!(!rate_limiter.is_allowed(b, protocol, dest_port, 1).await);
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_different_prefix_has_separate_rate_limit() {
+        let rate_limiter = RateLimiter::new(
+            true,
+            uniform_classes(1, Duration::from_secs(1), 1_000, Duration::from_secs(1), 50),
+            Duration::from_millis(50),
+        );
+        let protocol = IpNextHeaderProtocol::new(6); // TCP
+        let dest_port = 8080;
+
+        let a: IpAddr = "2001:db8:0:1::1".parse().unwrap();
+        let b: IpAddr = "2001:db8:0:2::1".parse().unwrap();
+
+        // `a` spends its own /64 bucket's only token.
+        assert!(rate_limiter.is_allowed(a, protocol, dest_port, 1).await);
+        // `b` is in a distinct /64, so it has an independent, still-full bucket.
+        assert!(rate_limiter.is_allowed(b, protocol, dest_port, 1).await);
+    }
+
+    #[tokio::test]
+    async fn test_burst_then_deny_then_refill() {
+        let rate_limiter = RateLimiter::new(
+            true,
+            uniform_classes(2, Duration::from_millis(1100), 1_000, Duration::from_secs(1), 50),
+            Duration::from_millis(50),
+        );
+        let src_ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
+        let protocol = IpNextHeaderProtocol::new(6); // TCP
+        let dest_port = 8080;
+
+        // The configured burst of 2 requests is allowed back-to-back...
+        assert!(rate_limiter.is_allowed(src_ip, protocol, dest_port, 1).await);
+        assert!(rate_limiter.is_allowed(src_ip, protocol, dest_port, 1).await);
+        // ...and the next one, with no tokens left, is denied.
+        assert!(!rate_limiter.is_allowed(src_ip, protocol, dest_port, 1).await);
+
+        // After the window elapses, the bucket has refilled.
+        sleep(Duration::from_millis(1100));
+        assert!(rate_limiter.is_allowed(src_ip, protocol, dest_port, 1).await);
+    }
+
+    #[tokio::test]
+    async fn test_bytes_budget_denies_independently_of_ops_budget() {
+        let rate_limiter = RateLimiter::new(
+            true,
+            uniform_classes(1_000, Duration::from_secs(1), 100, Duration::from_secs(1), 50),
+            Duration::from_millis(50),
+        );
+        let src_ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
+        let protocol = IpNextHeaderProtocol::new(6); // TCP
+        let dest_port = 8080;
+
+        // Plenty of Ops budget left, but the payload alone exceeds the Bytes budget.
+        assert!(!rate_limiter.is_allowed(src_ip, protocol, dest_port, 1_000).await);
+        // A small payload, well within both budgets, is still allowed.
+        assert!(rate_limiter.is_allowed(src_ip, protocol, dest_port, 1).await);
+    }
+
+    #[test]
+    fn test_limit_class_classification() {
+        let tcp = IpNextHeaderProtocol::new(6);
+        let udp = IpNextHeaderProtocol::new(17);
 
-        // Only 1 valid timestamp should remain
-        assert_eq!(rate_limiter.routes.get(&key).unwrap().len(), 1);
+        assert_eq!(LimitClass::classify(tcp, 22), LimitClass::Ssh);
+        assert_eq!(LimitClass::classify(udp, 53), LimitClass::Dns);
+        assert_eq!(LimitClass::classify(tcp, 8080), LimitClass::Default);
+        assert_eq!(LimitClass::classify(udp, 22), LimitClass::Default);
     }
 }